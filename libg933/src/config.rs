@@ -0,0 +1,57 @@
+//! Declarative description of a desired device state, loaded from a JSON or
+//! YAML file and applied with `Device::apply_config`
+
+use failure::Error;
+use lights;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Config key that applies to any device without a more specific entry
+pub const WILDCARD: &str = "*";
+
+/// The settings to apply to a single device
+///
+/// Every field is optional; an unset field is left untouched by
+/// `Device::apply_config`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    /// Desired light configuration
+    pub lights: Option<lights::Config>,
+    /// Desired sidetone volume (0 - 100)
+    pub sidetone_volume: Option<u8>,
+    /// Whether the startup effect should play
+    pub startup_effect: Option<bool>,
+    /// Whether G-key button reporting should be enabled
+    pub buttons: Option<bool>,
+}
+
+/// A full config file: a `DeviceConfig` per device serial, with an optional
+/// `"*"` entry applied to any device that has no entry of its own
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    devices: HashMap<String, DeviceConfig>,
+}
+
+impl Config {
+    /// Load a config from a JSON or YAML file, based on its extension
+    ///
+    /// Anything other than a `.yaml`/`.yml` extension is parsed as JSON.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path)?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => Ok(serde_yaml::from_str(&contents)?),
+            _ => Ok(serde_json::from_str(&contents)?),
+        }
+    }
+
+    /// The `DeviceConfig` for `serial`, falling back to the `"*"` wildcard
+    /// entry if there's no entry specific to that serial
+    pub fn for_serial(&self, serial: &str) -> Option<&DeviceConfig> {
+        self.devices
+            .get(serial)
+            .or_else(|| self.devices.get(WILDCARD))
+    }
+}
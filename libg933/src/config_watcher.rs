@@ -0,0 +1,62 @@
+//! Hot-reloads a `Config` file when it changes on disk
+
+use config::Config;
+use failure::Error;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::ffi::OsString;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+/// Watches a config file's parent directory for edits and reloads it
+///
+/// The directory is watched rather than the file itself, so editors that
+/// save by writing a new inode and renaming it over the old one (as most
+/// do) still trigger a reload.
+pub struct ConfigWatcher {
+    inotify: Inotify,
+    path: PathBuf,
+    file_name: OsString,
+}
+
+impl ConfigWatcher {
+    /// Start watching `path`'s parent directory for changes to `path` itself
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| format_err!("Config path has no file name: {}", path.display()))?
+            .to_owned();
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+
+        let inotify = Inotify::init(InitFlags::empty())?;
+        inotify.add_watch(
+            dir,
+            AddWatchFlags::IN_MODIFY | AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO,
+        )?;
+
+        Ok(Self {
+            inotify,
+            path: path.to_path_buf(),
+            file_name,
+        })
+    }
+
+    /// Drain pending inotify events, returning whether any of them touched
+    /// the watched config file rather than some other file in the directory
+    pub fn poll_changed(&mut self) -> Result<bool, Error> {
+        let events = self.inotify.read_events()?;
+        Ok(events
+            .iter()
+            .any(|event| event.name.as_ref() == Some(&self.file_name)))
+    }
+
+    /// Reload the config from disk
+    pub fn load(&self) -> Result<Config, Error> {
+        Config::load(&self.path)
+    }
+}
+
+impl AsRawFd for ConfigWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
@@ -0,0 +1,112 @@
+//! Hotplug-aware device discovery built on top of a udev monitor
+
+use failure::Error;
+use std::os::unix::io::{AsRawFd, RawFd};
+use udev::{Context, Device as UdevDevice, EventType, MonitorBuilder, MonitorSocket};
+
+use Device;
+
+const VENDOR_ID: &str = "046d";
+const PRODUCT_ID: &str = "0a5b";
+
+/// A hotplug event for a matching G933 device, keyed by its usb device's sysname
+pub enum DeviceEvent {
+    /// A matching device was plugged in and has been opened
+    Added(String, Device),
+    /// A previously added device was unplugged
+    ///
+    /// The `Device` itself is not included; callers should look it up by
+    /// sysname, call `Device::shutdown()` on it, and drop it.
+    Removed(String),
+}
+
+/// Watches udev for G933 hidraw hotplug events
+///
+/// The monitor's file descriptor is pollable (see `AsRawFd`), so it can be
+/// registered in a `select`/`poll` set with other fds, such as a
+/// `ConfigWatcher`'s. A `Device`'s hidraw fd is not exposed and cannot be
+/// added to that set; each `Device` services its HID reports on its own
+/// reader thread.
+pub struct DeviceMonitor {
+    socket: MonitorSocket,
+}
+
+impl DeviceMonitor {
+    /// Create a monitor filtered to hidraw events
+    pub fn new() -> Result<Self, Error> {
+        let context = Context::new()?;
+        let socket = MonitorBuilder::new(&context)?
+            .match_subsystem("hidraw")?
+            .listen()?;
+
+        Ok(Self { socket })
+    }
+
+    /// Block until the next matching hotplug event
+    ///
+    /// Non-matching udev traffic (other vendors, `change`/`bind` events,
+    /// etc.) is silently skipped. The sysname used to key `DeviceEvent` is
+    /// the usb device's, not the hidraw node's, matching `find_devices`.
+    pub fn recv(&mut self) -> Option<Result<DeviceEvent, Error>> {
+        loop {
+            let event = self.socket.next()?;
+            let device = event.device();
+
+            let usb_device = match usb_parent(&device) {
+                Some(usb_device) => usb_device,
+                None => continue,
+            };
+            if !has_ids(&usb_device) {
+                continue;
+            }
+
+            let sysname = usb_device.sysname().to_string_lossy().to_string();
+
+            match event.event_type() {
+                EventType::Add => {
+                    let devnode = match device.devnode() {
+                        Some(devnode) => devnode,
+                        None => continue,
+                    };
+
+                    return Some(Device::new(devnode).map(|device| DeviceEvent::Added(sysname, device)));
+                }
+                EventType::Remove => return Some(Ok(DeviceEvent::Removed(sysname))),
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl Iterator for DeviceMonitor {
+    type Item = Result<DeviceEvent, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+    }
+}
+
+impl AsRawFd for DeviceMonitor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+/// Walk up from a hidraw device to its usb device ancestor
+///
+/// The real sysfs chain is `hidraw -> hid -> usb_interface -> usb_device`,
+/// three hops up, not one.
+fn usb_parent(device: &UdevDevice) -> Option<UdevDevice> {
+    device
+        .parent_with_subsystem_devtype("usb", "usb_device")
+        .ok()
+        .and_then(|parent| parent)
+}
+
+fn has_ids(device: &UdevDevice) -> bool {
+    let vendor = device.attribute_value("idVendor").map(|v| v.to_string_lossy());
+    let product = device.attribute_value("idProduct").map(|v| v.to_string_lossy());
+
+    vendor.as_ref().map(|v| v.as_ref()) == Some(VENDOR_ID)
+        && product.as_ref().map(|v| v.as_ref()) == Some(PRODUCT_ID)
+}
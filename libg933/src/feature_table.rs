@@ -0,0 +1,64 @@
+//! Runtime discovery of HID++ 2.0 feature indices
+//!
+//! Feature indices aren't stable across firmware revisions: the root
+//! feature (ID `0x0000`, always at index `0x00`) has to be asked at runtime
+//! to resolve a standard feature ID to whatever index *this* device happens
+//! to expose it at.
+
+use failure::Error;
+use std::collections::HashMap;
+use Device;
+
+/// Device info
+pub const DEVICE_INFO: u16 = 0x0003;
+/// Device (friendly) name
+pub const DEVICE_NAME: u16 = 0x0005;
+/// RGB lighting
+pub const LIGHTS: u16 = 0x8070;
+/// G-key buttons
+pub const BUTTONS: u16 = 0x1b00;
+/// Sidetone volume
+pub const SIDETONE: u16 = 0x8300;
+/// Battery status
+pub const BATTERY: u16 = 0x1000;
+
+/// Every feature ID this crate knows how to use, resolved by `discover`
+const KNOWN_FEATURES: &[u16] = &[DEVICE_INFO, DEVICE_NAME, LIGHTS, BUTTONS, SIDETONE, BATTERY];
+
+/// Maps a standard HID++ 2.0 feature ID to its runtime index on a specific
+/// attached device
+pub struct FeatureTable {
+    indices: HashMap<u16, u8>,
+}
+
+impl FeatureTable {
+    /// An empty table, used as a placeholder before `discover` runs
+    pub(crate) fn empty() -> Self {
+        Self {
+            indices: HashMap::new(),
+        }
+    }
+
+    /// Resolve every feature in `KNOWN_FEATURES` against `device`'s root
+    /// feature, recording the index of each one the attached firmware
+    /// supports
+    pub(crate) fn discover(device: &mut Device) -> Result<Self, Error> {
+        let mut indices = HashMap::new();
+
+        for &feature in KNOWN_FEATURES {
+            let (index, _type, _version) = device.get_feature(feature)?;
+            // An index of 0 means the root feature itself, i.e. the
+            // firmware doesn't support this feature at all
+            if index != 0 {
+                indices.insert(feature, index);
+            }
+        }
+
+        Ok(Self { indices })
+    }
+
+    /// Look up the runtime index for a feature ID
+    pub fn index(&self, feature: u16) -> Option<u8> {
+        self.indices.get(&feature).cloned()
+    }
+}
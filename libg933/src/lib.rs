@@ -11,26 +11,61 @@ extern crate failure;
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
+extern crate nix;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate serde_json;
+extern crate serde_yaml;
 extern crate udev;
+extern crate uinput;
 
 #[macro_use]
 mod macros;
 pub mod battery;
 pub mod buttons;
+pub mod config;
+pub mod config_watcher;
 pub mod device_info;
+pub mod device_monitor;
+pub mod feature_table;
 pub mod lights;
+pub mod remap;
+
+pub use config::Config;
+pub use config_watcher::ConfigWatcher;
+pub use device_monitor::{DeviceEvent, DeviceMonitor};
+pub use feature_table::FeatureTable;
+pub use remap::{Remap, VirtualDevice};
 
 use failure::Error;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// The resend/timeout window for an in-flight `raw_request`
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A request waiting on a matching response, tracked so the reader thread can
+/// resend it once `REQUEST_TIMEOUT` elapses without one; `deadline` is `None`
+/// for passive subscriptions (e.g. `watch_buttons`) that are never resent
+struct PendingRequest {
+    data: [u8; 20],
+    sender: Sender<[u8; 20]>,
+    deadline: Option<Instant>,
+}
 
-type RequestsMap = HashMap<[u8; 4], Sender<[u8; 20]>>;
+type RequestsMap = HashMap<[u8; 4], PendingRequest>;
 
 /// Convert a struct that implements this trait to bytes
 pub trait AsBytes {
@@ -44,54 +79,134 @@ pub trait FromBytes {
     fn from_bytes(bytes: &[u8]) -> Self;
 }
 
+/// A cloneable handle that can shut down a `Device` from another thread
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<AtomicBool>);
+
+impl ShutdownHandle {
+    /// Signal the device to shut down
+    pub fn shutdown(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
 /// Contains a `HidDevice` and a vector of requests to be processed
 pub struct Device {
     file: File,
     requests: Arc<Mutex<RequestsMap>>,
+    running: Arc<AtomicBool>,
+    timer: Arc<TimerFd>,
+    features: feature_table::FeatureTable,
 }
 
 impl Device {
     /// Construct a new `Device` from a `HidDevice`
     pub fn new(path: &Path) -> Result<Self, Error> {
-        let device = Self {
+        let mut device = Self {
             file: OpenOptions::new().read(true).write(true).open(path)?,
             requests: Arc::new(Mutex::new(HashMap::new())),
+            running: Arc::new(AtomicBool::new(true)),
+            timer: Arc::new(TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::empty())?),
+            features: feature_table::FeatureTable::empty(),
         };
 
         let mut file = device.file.try_clone()?;
+        let mut writer = device.file.try_clone()?;
         let requests = Arc::clone(&device.requests);
+        let running = Arc::clone(&device.running);
+        let timer = Arc::clone(&device.timer);
         thread::spawn(move || {
-            use std::io::Read;
+            use std::io::{Read, Write};
 
             let mut data = [0u8; 20];
-
-            loop {
-                thread::sleep(Duration::from_millis(100));
-
-                let mut requests = requests.lock().unwrap();
-
-                // If there are no requests or it times out without reading anything, loop again
-                if requests.is_empty() || file.read(&mut data).unwrap() == 0 {
+            let mut poll_fds = [
+                PollFd::new(file.as_raw_fd(), PollFlags::POLLIN),
+                PollFd::new(timer.as_raw_fd(), PollFlags::POLLIN),
+            ];
+
+            while running.load(Ordering::SeqCst) {
+                // A timeout leaves no revents set and just rechecks `running`;
+                // retransmission is driven entirely by the timerfd below
+                if poll(&mut poll_fds, 1000).is_err() {
                     continue;
                 }
 
-                if let Some(sender) = requests.remove(&data[..4]) {
-                    debug!(
-                        "Got data from device: {}",
-                        data.iter()
-                            .map(|b| format!("{:02x}", b))
-                            .collect::<Vec<String>>()
-                            .join(" ")
-                    );
-                    sender.send(data).unwrap();
+                if let Some(events) = poll_fds[0].revents() {
+                    if events.contains(PollFlags::POLLIN) {
+                        match file.read(&mut data) {
+                            Ok(n) if n > 0 => {
+                                debug!(
+                                    "Got data from device: {}",
+                                    data.iter()
+                                        .map(|b| format!("{:02x}", b))
+                                        .collect::<Vec<String>>()
+                                        .join(" ")
+                                );
+
+                                let mut requests = requests.lock().unwrap();
+                                if let Some(pending) = requests.remove(&data[..4]) {
+                                    let _ = pending.sender.send(data);
+                                }
+                                rearm_timer(&timer, &requests);
+                            }
+                            Ok(_) => {}
+                            Err(err) => {
+                                // The device went away out from under us (unplugged
+                                // mid-read); stop servicing it instead of panicking
+                                // the thread and hanging every pending request.
+                                error!("Device read failed, shutting down: {}", err);
+                                // Dropping the senders makes waiting `raw_request`s fail
+                                requests.lock().unwrap().clear();
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                if let Some(events) = poll_fds[1].revents() {
+                    if events.contains(PollFlags::POLLIN) {
+                        let _ = timer.wait();
+
+                        let now = Instant::now();
+                        let mut requests = requests.lock().unwrap();
+                        for pending in requests.values_mut() {
+                            if pending.deadline.map_or(false, |deadline| deadline <= now) {
+                                let _ = writer.write_all(&pending.data);
+                                pending.deadline = Some(now + REQUEST_TIMEOUT);
+                            }
+                        }
+                        rearm_timer(&timer, &requests);
+                    }
                 }
             }
         });
 
+        device.features = feature_table::FeatureTable::discover(&mut device)?;
+
         Ok(device)
     }
 
-    /// Send a raw request to the device
+    /// Resolve a feature ID to its runtime index on this device
+    fn feature_index(&self, feature: u16) -> Result<u8, Error> {
+        self.features.index(feature).ok_or_else(|| {
+            format_err!(
+                "Feature {:#06x} is not supported by this device's firmware",
+                feature
+            )
+        })
+    }
+
+    /// Signal the reader thread (and any running `watch_buttons` loop) to stop
+    pub fn shutdown(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    /// A cloneable handle that can shut down this device from another thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(Arc::clone(&self.running))
+    }
+
+    /// Send a raw request to the device and wait for its response
     pub fn raw_request(&mut self, request: &[u8]) -> Result<[u8; 20], Error> {
         use std::io::Write;
 
@@ -100,41 +215,33 @@ impl Device {
         let mut data = [0u8; 20];
         data[..request.len()].copy_from_slice(request);
 
-        // Block until no similar requests are pending
-        loop {
-            let requests = self.requests.lock().unwrap();
-            if !requests.contains_key(&data[..4]) {
-                break;
-            }
-            thread::sleep(Duration::from_millis(100));
-        }
+        let mut header = [0u8; 4];
+        header.copy_from_slice(&data[..4]);
 
         let (sender, receiver) = mpsc::channel();
 
-        // Make sure we drop the lock before our write/read loop
-        {
-            let mut requests = self.requests.lock().unwrap();
+        self.file.write_all(&data)?;
+        debug!(
+            "Sent data to device: {}",
+            data.iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<String>>()
+                .join(" ")
+        );
 
-            let mut header = [0u8; 4];
-            header.copy_from_slice(&data[..4]);
-            requests.insert(header, sender);
-        }
+        let mut requests = self.requests.lock().unwrap();
+        requests.insert(
+            header,
+            PendingRequest {
+                data,
+                sender,
+                deadline: Some(Instant::now() + REQUEST_TIMEOUT),
+            },
+        );
+        rearm_timer(&self.timer, &requests);
+        drop(requests);
 
-        loop {
-            self.file.write_all(&data)?;
-            debug!(
-                "Sent data to device: {}",
-                data.iter()
-                    .map(|b| format!("{:02x}", b))
-                    .collect::<Vec<String>>()
-                    .join(" ")
-            );
-            match receiver.recv_timeout(Duration::from_secs(2)) {
-                Ok(response) => return Ok(response),
-                Err(mpsc::RecvTimeoutError::Timeout) => (),
-                Err(error) => return Err(error.into()),
-            }
-        }
+        Ok(receiver.recv()?)
     }
 
     /// Get info about a feature
@@ -169,7 +276,8 @@ impl Device {
 
     /// Get device info
     pub fn get_device_info(&mut self) -> Result<device_info::DeviceInfo, Error> {
-        let request = [0x11, 0xff, 0x02, 0x01];
+        let index = self.feature_index(feature_table::DEVICE_INFO)?;
+        let request = [0x11, 0xff, index, 0x01];
         self.raw_request(&request).map(|response| {
             device_info::DeviceInfo::from_bytes(&response[4..])
         })
@@ -177,12 +285,13 @@ impl Device {
 
     /// Get device name
     pub fn get_device_name(&mut self) -> Result<String, Error> {
-        let request = [0x11, 0xff, 0x03, 0x01];
+        let index = self.feature_index(feature_table::DEVICE_NAME)?;
+        let request = [0x11, 0xff, index, 0x01];
         let length = self.raw_request(&request)?[4];
 
         let mut name = String::new();
         for i in 0..length / 10 {
-            let request = [0x11, 0xff, 0x03, 0x11, i];
+            let request = [0x11, 0xff, index, 0x11, i];
             let response = &self.raw_request(&request)?[4..20]; // blaze it
                                                                 // Safe, probably
             name += str::from_utf8(response).unwrap();
@@ -196,18 +305,20 @@ impl Device {
 
     /// Set light configuration
     pub fn set_lights(&mut self, lights: &lights::Config) -> Result<lights::Config, Error> {
-        let request = v![0x11, 0xff, 0x04, 0x31, @lights.as_bytes()];
+        let index = self.feature_index(feature_table::LIGHTS)?;
+        let request = v![0x11, 0xff, index, 0x31, @lights.as_bytes()];
         Ok(lights::Config::from_bytes(&self.raw_request(&request)?))
     }
 
     /// Set startup effect on or off
     pub fn enable_startup_effect(&mut self, enable: bool) -> Result<(), Error> {
+        let index = self.feature_index(feature_table::LIGHTS)?;
         let enable_byte = if enable {
             0x01
         } else {
             0x02
         };
-        let request = [0x11, 0xff, 0x04, 0x51, 0x00, 0x01, enable_byte];
+        let request = [0x11, 0xff, index, 0x51, 0x00, 0x01, enable_byte];
         match self.raw_request(&request) {
             Ok(response) => {
                 ensure!(
@@ -224,7 +335,8 @@ impl Device {
 
     /// Set button reporting on or off
     pub fn enable_buttons(&mut self, enable: bool) -> Result<(), Error> {
-        let request = [0x11, 0xff, 0x05, 0x21, enable as u8];
+        let index = self.feature_index(feature_table::BUTTONS)?;
+        let request = [0x11, 0xff, index, 0x21, enable as u8];
         match self.raw_request(&request) {
             Ok(response) => {
                 ensure!(
@@ -241,7 +353,8 @@ impl Device {
 
     /// Set sidetone volume
     pub fn set_sidetone_volume(&mut self, volume: u8) -> Result<(), Error> {
-        let request = [0x11, 0xff, 0x07, 0x11, volume];
+        let index = self.feature_index(feature_table::SIDETONE)?;
+        let request = [0x11, 0xff, index, 0x11, volume];
         match self.raw_request(&request) {
             Ok(response) => {
                 ensure!(
@@ -258,32 +371,105 @@ impl Device {
 
     /// Get battery status and level
     pub fn get_battery_status(&mut self) -> Result<battery::BatteryStatus, Error> {
-        let request = [0x11, 0xff, 0x08, 0x01];
+        let index = self.feature_index(feature_table::BATTERY)?;
+        let request = [0x11, 0xff, index, 0x01];
         Ok(battery::BatteryStatus::from_bytes(
             &self.raw_request(&request)?,
         ))
     }
 
-    /// Watch for button presses/releases (g1, g2, g3)
-    pub fn watch_buttons(&mut self, callback: fn(buttons::Buttons)) -> Result<(), Error> {
+    /// Apply every setting present in `config` for this device's serial (or
+    /// the `"*"` wildcard entry), collecting any failures instead of bailing
+    /// out on the first one
+    pub fn apply_config(&mut self, config: &config::Config) -> Result<(), Error> {
+        let serial = self.get_device_info()?.serial;
+        let device_config = match config.for_serial(&serial) {
+            Some(device_config) => device_config,
+            None => return Ok(()),
+        };
+
+        let mut errors = Vec::new();
+
+        if let Some(ref lights) = device_config.lights {
+            if let Err(error) = self.set_lights(lights) {
+                errors.push(error);
+            }
+        }
+        if let Some(volume) = device_config.sidetone_volume {
+            if let Err(error) = self.set_sidetone_volume(volume) {
+                errors.push(error);
+            }
+        }
+        if let Some(enable) = device_config.startup_effect {
+            if let Err(error) = self.enable_startup_effect(enable) {
+                errors.push(error);
+            }
+        }
+        if let Some(enable) = device_config.buttons {
+            if let Err(error) = self.enable_buttons(enable) {
+                errors.push(error);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format_err!(
+                "Failed to apply {} setting(s): {}",
+                errors.len(),
+                errors
+                    .iter()
+                    .map(|error| error.to_string())
+                    .collect::<Vec<String>>()
+                    .join("; "),
+            ))
+        }
+    }
+
+    /// Watch for button presses/releases (g1, g2, g3), until `shutdown`/
+    /// `ShutdownHandle::shutdown` is called
+    pub fn watch_buttons(&mut self, mut callback: impl FnMut(buttons::Buttons)) -> Result<(), Error> {
         let (sender, receiver) = mpsc::channel();
+        let header = [0x11, 0xff, self.feature_index(feature_table::BUTTONS)?, 0x00];
 
-        // Loop and keep adding the request to our pending request map
-        loop {
-            // Make sure we drop the lock before we try reading
+        while self.running.load(Ordering::SeqCst) {
             {
                 let mut requests = self.requests.lock().unwrap();
-
-                let header = [0x11, 0xff, 0x05, 0x00];
-                requests.insert(header, sender.clone());
+                requests.insert(
+                    header,
+                    PendingRequest {
+                        data: [0u8; 20],
+                        sender: sender.clone(),
+                        deadline: None,
+                    },
+                );
             }
 
-            match receiver.recv_timeout(Duration::from_secs(2)) {
+            match receiver.recv_timeout(Duration::from_millis(200)) {
                 Ok(response) => callback(buttons::Buttons::from_bytes(&response[4..])),
                 Err(mpsc::RecvTimeoutError::Timeout) => (),
                 Err(error) => return Err(error.into()),
             }
         }
+
+        Ok(())
+    }
+}
+
+/// Arm the reader thread's `TimerFd` to the earliest pending request's
+/// deadline, or disarm it if nothing is outstanding
+fn rearm_timer(timer: &TimerFd, requests: &RequestsMap) {
+    match requests.values().filter_map(|pending| pending.deadline).min() {
+        Some(deadline) => {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let _ = timer.set(
+                Expiration::OneShot(TimeSpec::from(remaining)),
+                TimerSetTimeFlags::empty(),
+            );
+        }
+        None => {
+            let _ = timer.unset();
+        }
     }
 }
 
@@ -0,0 +1,252 @@
+//! Remaps G-key presses to synthetic key events on a virtual uinput device
+
+use buttons::Buttons;
+use failure::Error;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use uinput::event::keyboard::Key;
+use uinput::Device as UinputDevice;
+
+/// One of the three G-keys on the headset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GKey {
+    /// G1
+    G1,
+    /// G2
+    G2,
+    /// G3
+    G3,
+}
+
+impl GKey {
+    fn parse(name: &str) -> Result<Self, Error> {
+        match name {
+            "g1" => Ok(GKey::G1),
+            "g2" => Ok(GKey::G2),
+            "g3" => Ok(GKey::G3),
+            name => bail!("Unknown G-key: {}", name),
+        }
+    }
+}
+
+/// One step of an `Action`: a key, plus the modifiers held just for it
+#[derive(Debug, Clone)]
+struct Step {
+    modifiers: Vec<Key>,
+    key: Key,
+}
+
+/// A remap target: one or more steps, each pressed/released in order
+///
+/// A single keysym is just one step with no modifiers; a modifier+key combo
+/// is one step with one or more modifiers. A macro is written as multiple
+/// `+`-joined steps separated by commas, e.g. `"ctrl+c,alt+tab"`, and each
+/// step's own modifiers are held only for that step, not the whole sequence.
+#[derive(Debug, Clone)]
+pub struct Action {
+    steps: Vec<Step>,
+}
+
+impl Action {
+    /// Parse a target spec like `"ctrl+c"`, `"XF86AudioPlay"`, or a
+    /// comma-separated macro sequence like `"ctrl+c,alt+tab"`
+    fn parse(spec: &str) -> Result<Self, Error> {
+        let steps = spec
+            .split(',')
+            .map(|step| {
+                let mut names: Vec<&str> = step.split('+').map(str::trim).collect();
+                let key = names
+                    .pop()
+                    .ok_or_else(|| format_err!("Empty remap target"))?;
+
+                Ok(Step {
+                    modifiers: names
+                        .iter()
+                        .map(|name| key_from_name(name))
+                        .collect::<Result<Vec<Key>, Error>>()?,
+                    key: key_from_name(key)?,
+                })
+            })
+            .collect::<Result<Vec<Step>, Error>>()?;
+
+        Ok(Self { steps })
+    }
+}
+
+/// Maps each G-key to the action it should trigger
+pub type Remap = HashMap<GKey, Action>;
+
+/// Parse a remap file (JSON or YAML, based on extension) like
+/// `{"g1": "XF86AudioPlay", "g2": "ctrl+c"}` into a `Remap` table
+pub fn load(path: &Path) -> Result<Remap, Error> {
+    let contents = fs::read_to_string(path)?;
+    let raw: HashMap<String, String> = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&contents)?,
+        _ => serde_json::from_str(&contents)?,
+    };
+
+    raw.iter()
+        .map(|(key, target)| Ok((GKey::parse(key)?, Action::parse(target)?)))
+        .collect()
+}
+
+/// A uinput keyboard created with the superset of key codes referenced by a
+/// `Remap` table, used to emit synthetic key events for G-key presses
+pub struct VirtualDevice {
+    device: UinputDevice,
+}
+
+impl VirtualDevice {
+    /// Create and register a uinput device capable of emitting every key
+    /// referenced by `remap`
+    pub fn new(remap: &Remap) -> Result<Self, Error> {
+        let mut builder = ::uinput::default()?.name("g933-remap")?;
+        for action in remap.values() {
+            for step in &action.steps {
+                for &key in step.modifiers.iter().chain(Some(&step.key)) {
+                    builder = builder.event(key)?;
+                }
+            }
+        }
+
+        Ok(Self {
+            device: builder.create()?,
+        })
+    }
+
+    /// Press every step of `action` in order
+    ///
+    /// Each step but the last is pressed and released (with its own
+    /// modifiers) immediately so it registers as its own keystroke; the last
+    /// step's key and modifiers are left held down until `release` is
+    /// called, same as a single-step action.
+    pub fn press(&mut self, action: &Action) -> Result<(), Error> {
+        let (last, steps) = match action.steps.split_last() {
+            Some((last, steps)) => (last, steps),
+            None => return Ok(()),
+        };
+
+        for step in steps {
+            self.press_step(step)?;
+            self.release_step(step)?;
+        }
+        self.press_step(last)
+    }
+
+    /// Release the last step pressed by `press`
+    pub fn release(&mut self, action: &Action) -> Result<(), Error> {
+        match action.steps.last() {
+            Some(last) => self.release_step(last),
+            None => Ok(()),
+        }
+    }
+
+    fn press_step(&mut self, step: &Step) -> Result<(), Error> {
+        for &modifier in &step.modifiers {
+            self.device.press(&modifier)?;
+        }
+        self.device.press(&step.key)?;
+        self.device.synchronize()?;
+        Ok(())
+    }
+
+    fn release_step(&mut self, step: &Step) -> Result<(), Error> {
+        self.device.release(&step.key)?;
+        for &modifier in step.modifiers.iter().rev() {
+            self.device.release(&modifier)?;
+        }
+        self.device.synchronize()?;
+        Ok(())
+    }
+}
+
+/// Tracks the previously-seen `Buttons` state so callers can diff against a
+/// newly-parsed one and get press/release edges, rather than raw levels
+#[derive(Debug, Default)]
+pub struct EdgeTracker {
+    previous: (bool, bool, bool),
+}
+
+impl EdgeTracker {
+    /// Start tracking from an all-released state
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Diff `current` against the last-seen state, returning the G-keys
+    /// that just transitioned along with whether they're now pressed
+    pub fn transitions(&mut self, current: &Buttons) -> Vec<(GKey, bool)> {
+        let now = (current.g1, current.g2, current.g3);
+        let mut transitions = Vec::new();
+
+        if now.0 != self.previous.0 {
+            transitions.push((GKey::G1, now.0));
+        }
+        if now.1 != self.previous.1 {
+            transitions.push((GKey::G2, now.1));
+        }
+        if now.2 != self.previous.2 {
+            transitions.push((GKey::G3, now.2));
+        }
+
+        self.previous = now;
+        transitions
+    }
+}
+
+/// Resolve a key/modifier name (case-insensitively) to a uinput keysym
+///
+/// Covers modifiers, letters, digits, and a handful of common media keys;
+/// extend as more remap targets come up.
+fn key_from_name(name: &str) -> Result<Key, Error> {
+    Ok(match name.to_lowercase().as_str() {
+        "ctrl" | "control" => Key::LeftControl,
+        "shift" => Key::LeftShift,
+        "alt" => Key::LeftAlt,
+        "meta" | "super" | "win" => Key::LeftMeta,
+        "a" => Key::A,
+        "b" => Key::B,
+        "c" => Key::C,
+        "d" => Key::D,
+        "e" => Key::E,
+        "f" => Key::F,
+        "g" => Key::G,
+        "h" => Key::H,
+        "i" => Key::I,
+        "j" => Key::J,
+        "k" => Key::K,
+        "l" => Key::L,
+        "m" => Key::M,
+        "n" => Key::N,
+        "o" => Key::O,
+        "p" => Key::P,
+        "q" => Key::Q,
+        "r" => Key::R,
+        "s" => Key::S,
+        "t" => Key::T,
+        "u" => Key::U,
+        "v" => Key::V,
+        "w" => Key::W,
+        "x" => Key::X,
+        "y" => Key::Y,
+        "z" => Key::Z,
+        "0" => Key::_0,
+        "1" => Key::_1,
+        "2" => Key::_2,
+        "3" => Key::_3,
+        "4" => Key::_4,
+        "5" => Key::_5,
+        "6" => Key::_6,
+        "7" => Key::_7,
+        "8" => Key::_8,
+        "9" => Key::_9,
+        "xf86audioplay" => Key::PlayPause,
+        "xf86audionext" => Key::NextSong,
+        "xf86audioprev" => Key::PreviousSong,
+        "xf86audiomute" => Key::Mute,
+        "xf86audioraisevolume" => Key::VolumeUp,
+        "xf86audiolowervolume" => Key::VolumeDown,
+        other => bail!("Unknown remap key: {}", other),
+    })
+}
@@ -2,211 +2,456 @@
 #![cfg_attr(feature = "cargo-clippy", allow(useless_format))]
 
 extern crate clap;
+extern crate clap_complete;
 extern crate env_logger;
 #[macro_use]
 extern crate failure;
-#[macro_use]
-extern crate indoc;
 extern crate libg933;
 #[macro_use]
 extern crate log;
+extern crate nix;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
-use clap::{App, SubCommand};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
 use failure::Error;
+use nix::poll::{poll, PollFd, PollFlags};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::thread;
 
-fn run() -> Result<(), Error> {
-    #[cfg_attr(rustfmt, rustfmt_skip)]
-    let matches = App::new("g933control")
-        .author("Ash Lea <ashlea@protonmail.com>")
-        .about("Configure and control the Logitech G933 Gaming Headset")
-        .subcommand(SubCommand::with_name("list")
-            .about("List attached devices")
-        )
-        .after_help(indoc!("
-            Use --help with any subcommand for more information
-        "))
-        .subcommand(SubCommand::with_name("get")
-            .about("Get a property of a device")
-            .args_from_usage("
-                -d, --device [device] 'Device to get property from'
-                <property>            'Property to get'
-            ")
-            .after_help(indoc!("
-                Valid options for `property` are:
-                    battery
-            "))
-        )
-        .subcommand(SubCommand::with_name("set")
-            .about("Set a property of a device")
-            .args_from_usage("
-                -d, --device [device] 'Device to set property on'
-                <property>            'Property to set'
-                <value>               'Value of property'
-            ")
-            .after_help(indoc!("
-                Valid options for `property` are:
-                    buttons (bool)
-                    sidetone_volume (0 - 100)
-                    startup_effect (bool)
-            "))
-        )
-        .subcommand(SubCommand::with_name("watch")
-            .about("Watch for events")
-            .args_from_usage("
-                -d, --device [device] 'Device to watch'
-                <event>               'Event to watch for'
-            ")
-            .after_help(indoc!("
-                Valid options for `event` are:
-                    buttons
-            "))
-        )
-        .subcommand(SubCommand::with_name("raw")
-            .about("Send a raw request to a device")
-            .args_from_usage("
-                -d, --device [device] 'Device to send request to'
-                -f, --format [format] 'Response format'
-                <request>...          'Bytes of request separated by spaces'
-            ")
-            .after_help(indoc!("
-                NOTE: The bytes of the request will always be parsed as base 16
-            "))
-        )
-        .get_matches();
-
-    if matches.subcommand_matches("list").is_some() {
-        for (sysname, mut device) in libg933::find_devices()? {
-            println!("Device {}: {}", sysname, device.get_device_name()?);
+/// Configure and control the Logitech G933 Gaming Headset
+#[derive(Parser)]
+#[command(name = "g933control", author = "Ash Lea <ashlea@protonmail.com>")]
+#[command(after_help = "Use --help with any subcommand for more information")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List attached devices
+    List,
+    /// Get a property of a device
+    Get {
+        /// Device to get property from
+        #[arg(short, long)]
+        device: Option<String>,
+        /// Property to get
+        #[arg(value_enum)]
+        property: GetProperty,
+    },
+    /// Set a property of a device
+    Set {
+        /// Device to set property on
+        #[arg(short, long)]
+        device: Option<String>,
+        /// Property to set
+        #[arg(value_enum)]
+        property: SetProperty,
+        /// Value of property
+        value: String,
+    },
+    /// Watch for events
+    Watch {
+        /// Device to watch
+        #[arg(short, long)]
+        device: Option<String>,
+        /// Event to watch for
+        #[arg(value_enum)]
+        event: WatchEvent,
+    },
+    /// Apply a saved config to every matching device
+    Apply {
+        /// Config file to apply (JSON or YAML)
+        file: PathBuf,
+    },
+    /// Apply a config and keep it applied as it changes on disk
+    Daemon {
+        /// Config file to load and watch
+        #[arg(long)]
+        config: PathBuf,
+    },
+    /// Remap G-key presses to key events on a virtual input device
+    Remap {
+        /// Device to remap
+        #[arg(short, long)]
+        device: Option<String>,
+        /// Remap file mapping G-keys to key combos
+        file: PathBuf,
+    },
+    /// Send a raw request to a device
+    ///
+    /// NOTE: The bytes of the request will always be parsed as base 16
+    Raw {
+        /// Device to send request to
+        #[arg(short, long)]
+        device: Option<String>,
+        /// Response format
+        #[arg(short, long, value_enum, default_value_t = RawFormat::Bytes)]
+        format: RawFormat,
+        /// Bytes of request, separated by spaces
+        request: Vec<String>,
+    },
+    /// Generate a shell completion script
+    Completions {
+        /// Shell to generate a completion script for
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+#[value(rename_all = "snake_case")]
+enum GetProperty {
+    Battery,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+#[value(rename_all = "snake_case")]
+enum SetProperty {
+    Buttons,
+    SidetoneVolume,
+    StartupEffect,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+#[value(rename_all = "snake_case")]
+enum WatchEvent {
+    Buttons,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+#[value(rename_all = "snake_case")]
+enum RawFormat {
+    Bytes,
+    String,
+    Json,
+}
+
+/// The 20-byte HID++ response, with the header fields split out from the
+/// payload so scripts consuming `--format json` don't have to re-parse hex
+#[derive(Serialize)]
+struct RawResponse {
+    report_id: u8,
+    device_id: u8,
+    feature_index: u8,
+    function_id: u8,
+    software_id: u8,
+    payload: Vec<u8>,
+}
+
+impl From<[u8; 20]> for RawResponse {
+    fn from(response: [u8; 20]) -> Self {
+        Self {
+            report_id: response[0],
+            device_id: response[1],
+            feature_index: response[2],
+            function_id: response[3] >> 4,
+            software_id: response[3] & 0x0f,
+            payload: response[4..].to_vec(),
         }
     }
+}
 
-    if let Some(matches) = matches.subcommand_matches("get") {
-        let property = matches.value_of("property").unwrap();
-        let mut devices = libg933::find_devices()?;
-        let mut device = match matches.value_of("device") {
-            Some(sysname) => devices
-                .get_mut(sysname)
-                .ok_or_else(|| format_err!("No such device: {}", sysname))?,
-            None => devices
-                .values_mut()
-                .next()
-                .ok_or_else(|| format_err!("No devices found"))?,
+fn device_by_sysname<'a>(
+    devices: &'a mut std::collections::HashMap<String, libg933::Device>,
+    sysname: &Option<String>,
+) -> Result<&'a mut libg933::Device, Error> {
+    match sysname {
+        Some(sysname) => devices
+            .get_mut(sysname)
+            .ok_or_else(|| format_err!("No such device: {}", sysname)),
+        None => devices
+            .values_mut()
+            .next()
+            .ok_or_else(|| format_err!("No devices found")),
+    }
+}
+
+/// Run `Device::watch_buttons` on its own thread so the caller is free to
+/// keep polling a `DeviceMonitor` for hotplug events, and return a handle
+/// that can stop the loop once the device goes away
+fn spawn_watch(
+    sysname: String,
+    mut device: libg933::Device,
+    event: WatchEvent,
+) -> (String, libg933::ShutdownHandle, thread::JoinHandle<()>) {
+    let handle = device.shutdown_handle();
+    let thread_sysname = sysname.clone();
+
+    let thread = thread::spawn(move || {
+        let result = match event {
+            WatchEvent::Buttons => device.watch_buttons(|buttons| {
+                println!("g1: {}, g2: {}, g3: {}", buttons.g1, buttons.g2, buttons.g3);
+            }),
         };
 
-        match property {
-            "battery" => {
-                use libg933::battery::ChargingStatus::*;
+        if let Err(error) = result {
+            error!("Watch loop for {} ended: {}", thread_sysname, error);
+        }
+    });
 
-                let battery_status = device.get_battery_status()?;
-                let charging_status = match battery_status.charging_status {
-                    Discharging => "discharging",
-                    Charging(false) => "charging (ascending)",
-                    Charging(true) => "charging (descending)",
-                    Full => "full",
-                };
+    (sysname, handle, thread)
+}
 
-                println!(
-                    "Status: {:.01}% [{}]",
-                    battery_status.charge, charging_status
-                );
+fn run() -> Result<(), Error> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::List => {
+            for (sysname, mut device) in libg933::find_devices()? {
+                println!("Device {}: {}", sysname, device.get_device_name()?);
             }
-            p => println!("Invalid property: {}", p),
         }
-    }
 
-    if let Some(matches) = matches.subcommand_matches("set") {
-        let property = matches.value_of("property").unwrap();
-        let value = matches.value_of("value").unwrap();
-        let mut devices = libg933::find_devices()?;
-        let mut device = match matches.value_of("device") {
-            Some(sysname) => devices
-                .get_mut(sysname)
-                .ok_or_else(|| format_err!("No such device: {}", sysname))?,
-            None => devices
-                .values_mut()
-                .next()
-                .ok_or_else(|| format_err!("No devices found"))?,
-        };
+        Command::Get { device, property } => {
+            let mut devices = libg933::find_devices()?;
+            let device = device_by_sysname(&mut devices, &device)?;
+
+            match property {
+                GetProperty::Battery => {
+                    use libg933::battery::ChargingStatus::*;
 
-        match property {
-            "buttons" => {
-                let enable = value.parse::<bool>()?;
-                device.enable_buttons(enable)?;
+                    let battery_status = device.get_battery_status()?;
+                    let charging_status = match battery_status.charging_status {
+                        Discharging => "discharging",
+                        Charging(false) => "charging (ascending)",
+                        Charging(true) => "charging (descending)",
+                        Full => "full",
+                    };
+
+                    println!(
+                        "Status: {:.01}% [{}]",
+                        battery_status.charge, charging_status
+                    );
+                }
             }
-            "sidetone_volume" => {
-                let volume = value.parse::<u8>()?;
-                assert!(volume <= 100);
-                device.set_sidetone_volume(volume)?;
+        }
+
+        Command::Set {
+            device,
+            property,
+            value,
+        } => {
+            let mut devices = libg933::find_devices()?;
+            let device = device_by_sysname(&mut devices, &device)?;
+
+            match property {
+                SetProperty::Buttons => {
+                    device.enable_buttons(value.parse::<bool>()?)?;
+                }
+                SetProperty::SidetoneVolume => {
+                    let volume = value.parse::<u8>()?;
+                    ensure!(volume <= 100, "sidetone_volume must be 0 - 100");
+                    device.set_sidetone_volume(volume)?;
+                }
+                SetProperty::StartupEffect => {
+                    device.enable_startup_effect(value.parse::<bool>()?)?;
+                }
             }
-            "startup_effect" => {
-                let enable = value.parse::<bool>()?;
-                device.enable_startup_effect(enable)?;
+        }
+
+        Command::Watch {
+            device: target,
+            event,
+        } => {
+            let mut devices = libg933::find_devices()?;
+            let mut monitor = libg933::DeviceMonitor::new()?;
+
+            let initial_sysname = match &target {
+                Some(sysname) if devices.contains_key(sysname) => Some(sysname.clone()),
+                Some(_) => None,
+                None => devices.keys().next().cloned(),
+            };
+
+            let mut watching = initial_sysname
+                .and_then(|sysname| devices.remove(&sysname).map(|device| (sysname, device)))
+                .map(|(sysname, device)| spawn_watch(sysname, device, event));
+
+            // Survive the headset sleeping/reconnecting instead of hanging
+            // forever on a device that's gone: watch udev for hotplug
+            // events and restart the watch loop whenever our device (or,
+            // with no device specified, any matching device) comes back.
+            loop {
+                let mut poll_fds = [PollFd::new(monitor.as_raw_fd(), PollFlags::POLLIN)];
+                poll(&mut poll_fds, -1)?;
+
+                if !poll_fds[0]
+                    .revents()
+                    .map_or(false, |events| events.contains(PollFlags::POLLIN))
+                {
+                    continue;
+                }
+
+                let event_result = match monitor.recv() {
+                    Some(event_result) => event_result,
+                    None => continue,
+                };
+
+                match event_result? {
+                    libg933::DeviceEvent::Added(sysname, device) => {
+                        let wanted = target.as_ref().map_or(true, |name| *name == sysname);
+                        if wanted && watching.is_none() {
+                            info!("Device {} connected, watching", sysname);
+                            watching = Some(spawn_watch(sysname, device, event));
+                        }
+                    }
+                    libg933::DeviceEvent::Removed(sysname) => {
+                        if watching.as_ref().map_or(false, |watch| watch.0 == sysname) {
+                            info!("Device {} disconnected, waiting for it to return", sysname);
+                            let (_, handle, thread) = watching.take().unwrap();
+                            handle.shutdown();
+                            let _ = thread.join();
+                        }
+                    }
+                }
             }
-            p => println!("Invalid property: {}", p),
         }
-    }
 
-    if let Some(matches) = matches.subcommand_matches("watch") {
-        let event = matches.value_of("event").unwrap();
-        let mut devices = libg933::find_devices()?;
-        let mut device = match matches.value_of("device") {
-            Some(sysname) => devices
-                .get_mut(sysname)
-                .ok_or_else(|| format_err!("No such device: {}", sysname))?,
-            None => devices
-                .values_mut()
-                .next()
-                .ok_or_else(|| format_err!("No devices found"))?,
-        };
+        Command::Apply { file } => {
+            let config = libg933::config::Config::load(&file)?;
 
-        match event {
-            "buttons" => {
-                device.watch_buttons(|buttons| {
-                    println!("g1: {}, g2: {}, g3: {}", buttons.g1, buttons.g2, buttons.g3);
-                })?;
+            for (sysname, mut device) in libg933::find_devices()? {
+                if let Err(error) = device.apply_config(&config) {
+                    error!("Failed to apply config to {}: {}", sysname, error);
+                }
             }
-            e => println!("Invalid event: {}", e),
         }
-    }
 
-    if let Some(matches) = matches.subcommand_matches("raw") {
-        let format = matches.value_of("format").unwrap_or("bytes");
-        let mut devices = libg933::find_devices()?;
-        let mut device = match matches.value_of("device") {
-            Some(sysname) => devices
-                .get_mut(sysname)
-                .ok_or_else(|| format_err!("No such device: {}", sysname))?,
-            None => devices
-                .values_mut()
-                .next()
-                .ok_or_else(|| format_err!("No devices found"))?,
-        };
+        Command::Daemon { config: path } => {
+            let mut watcher = libg933::ConfigWatcher::new(&path)?;
+            let mut config = watcher.load()?;
 
-        let request = matches
-            .values_of("request")
-            .unwrap()
-            .flat_map(|bytes| {
-                bytes
-                    .split_whitespace()
-                    .map(|b| u8::from_str_radix(b, 16).unwrap())
-            })
-            .collect::<Vec<u8>>();
-
-        match format {
-            "bytes" => println!(
-                "{}",
-                device
-                    .raw_request(&request)?
-                    .iter()
-                    .map(|b| format!("{:02x}", b))
-                    .collect::<Vec<String>>()
-                    .join(" ")
-            ),
-            "string" => println!(
-                "{}",
-                String::from_utf8_lossy(&device.raw_request(&request)?)
-            ),
-            format => bail!("Invalid format: {}", format),
+            let mut devices = libg933::find_devices()?;
+            for (sysname, device) in devices.iter_mut() {
+                if let Err(error) = device.apply_config(&config) {
+                    error!("Failed to apply config to {}: {}", sysname, error);
+                }
+            }
+
+            let mut monitor = libg933::DeviceMonitor::new()?;
+
+            loop {
+                let mut poll_fds = [
+                    PollFd::new(monitor.as_raw_fd(), PollFlags::POLLIN),
+                    PollFd::new(watcher.as_raw_fd(), PollFlags::POLLIN),
+                ];
+                poll(&mut poll_fds, -1)?;
+
+                if poll_fds[0]
+                    .revents()
+                    .map_or(false, |events| events.contains(PollFlags::POLLIN))
+                {
+                    if let Some(event) = monitor.recv() {
+                        match event? {
+                            libg933::DeviceEvent::Added(sysname, mut device) => {
+                                info!("Device {} connected", sysname);
+                                if let Err(error) = device.apply_config(&config) {
+                                    error!("Failed to apply config to {}: {}", sysname, error);
+                                }
+                                devices.insert(sysname, device);
+                            }
+                            libg933::DeviceEvent::Removed(sysname) => {
+                                info!("Device {} disconnected", sysname);
+                                if let Some(device) = devices.remove(&sysname) {
+                                    device.shutdown();
+                                }
+                            }
+                        }
+                    }
+                }
+
+                if poll_fds[1]
+                    .revents()
+                    .map_or(false, |events| events.contains(PollFlags::POLLIN))
+                    && watcher.poll_changed()?
+                {
+                    info!("Config file changed, reapplying to all devices");
+                    config = watcher.load()?;
+                    for (sysname, device) in devices.iter_mut() {
+                        if let Err(error) = device.apply_config(&config) {
+                            error!("Failed to apply config to {}: {}", sysname, error);
+                        }
+                    }
+                }
+            }
+        }
+
+        Command::Remap { device, file } => {
+            let remap = libg933::remap::load(&file)?;
+            let mut virtual_device = libg933::VirtualDevice::new(&remap)?;
+            let mut tracker = libg933::remap::EdgeTracker::new();
+
+            let mut devices = libg933::find_devices()?;
+            let device = device_by_sysname(&mut devices, &device)?;
+
+            device.enable_buttons(true)?;
+            device.watch_buttons(|buttons| {
+                for (key, pressed) in tracker.transitions(&buttons) {
+                    let action = match remap.get(&key) {
+                        Some(action) => action,
+                        None => continue,
+                    };
+
+                    let result = if pressed {
+                        virtual_device.press(action)
+                    } else {
+                        virtual_device.release(action)
+                    };
+
+                    if let Err(error) = result {
+                        error!("Failed to emit remapped key event: {}", error);
+                    }
+                }
+            })?;
+        }
+
+        Command::Raw {
+            device,
+            format,
+            request,
+        } => {
+            let mut devices = libg933::find_devices()?;
+            let device = device_by_sysname(&mut devices, &device)?;
+
+            let request = request
+                .iter()
+                .flat_map(|bytes| {
+                    bytes
+                        .split_whitespace()
+                        .map(|b| u8::from_str_radix(b, 16).unwrap())
+                })
+                .collect::<Vec<u8>>();
+
+            match format {
+                RawFormat::Bytes => println!(
+                    "{}",
+                    device
+                        .raw_request(&request)?
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<Vec<String>>()
+                        .join(" ")
+                ),
+                RawFormat::String => println!(
+                    "{}",
+                    String::from_utf8_lossy(&device.raw_request(&request)?)
+                ),
+                RawFormat::Json => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&RawResponse::from(device.raw_request(&request)?))?
+                ),
+            }
+        }
+
+        Command::Completions { shell } => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
         }
     }
 